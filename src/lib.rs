@@ -97,21 +97,111 @@
 
 extern crate "rustc-serialize" as rustc_serialize;
 extern crate bincode;
+extern crate time;
 
-use bincode::{encode, EncodingError, decode, DecodingError, SizeLimit};
+use bincode::{encode_into, EncodingError, decode, DecodingError, SizeLimit};
 use rustc_serialize::{Decodable, Encodable};
 use std::sync::mpsc;
+use std::cmp;
 use std::error::{Error, FromError};
-use std::io::{IoError, Reader, Writer};
+use std::io::{BufReader, IoError, IoErrorKind, IoResult, MemWriter, Reader, Writer};
 use std::sync::Future;
 
+mod net;
+pub mod rpc;
 pub mod tcp;
+pub mod unix;
+
+/// An address that a channel can be created against.
+///
+/// This lets callers pick a transport (and the socket family it runs over)
+/// without having to `use` a specific protocol module themselves.
+pub enum Addr<'a> {
+    Tcp(&'a str),
+    Unix(&'a str),
+}
+
+/// A `Sender` that dispatches to whichever transport a channel was opened
+/// with. Returned by the top-level `channel` function.
+pub enum ChannelSender<T: Encodable + Send> {
+    Tcp(tcp::ClientSender<T>),
+    Unix(unix::ClientSender<T>),
+}
+
+impl<T> Sender<T> for ChannelSender<T> where T: Encodable + Send {
+    fn send(&mut self, t: T) -> Future<Result<(), SenderError<T>>> {
+        match *self {
+            ChannelSender::Tcp(ref mut s) => s.send(t),
+            ChannelSender::Unix(ref mut s) => s.send(t),
+        }
+    }
+
+    fn send_with_body(&mut self, t: T, body: Box<Reader + Send>) -> Future<Result<(), SenderError<T>>> {
+        match *self {
+            ChannelSender::Tcp(ref mut s) => s.send_with_body(t, body),
+            ChannelSender::Unix(ref mut s) => s.send_with_body(t, body),
+        }
+    }
+}
+
+/// A `Receiver` that dispatches to whichever transport a channel was opened
+/// with. Returned by the top-level `channel` function.
+pub enum ChannelReceiver<S: Decodable + Send> {
+    Tcp(tcp::ClientReceiver<S>),
+    Unix(unix::ClientReceiver<S>),
+}
+
+impl<S> Receiver<S> for ChannelReceiver<S> where S: Decodable + Send {
+    fn try_recv(&mut self) -> Result<S, ReceiverError<S>> {
+        match *self {
+            ChannelReceiver::Tcp(ref mut r) => r.try_recv(),
+            ChannelReceiver::Unix(ref mut r) => r.try_recv(),
+        }
+    }
+
+    fn try_recv_with_body(&mut self) -> Result<(S, BodyReader), ReceiverError<S>> {
+        match *self {
+            ChannelReceiver::Tcp(ref mut r) => r.try_recv_with_body(),
+            ChannelReceiver::Unix(ref mut r) => r.try_recv_with_body(),
+        }
+    }
+}
+
+/// Create a channel over either a TCP or a Unix domain socket connection,
+/// picking the transport based on the `Addr` variant.
+///
+/// This is a thin convenience wrapper around `tcp::client_channel` and
+/// `unix::client_channel` for callers who want to pick the socket family at
+/// runtime (or keep it configurable) without otherwise changing their code.
+pub fn channel<T: Encodable + Send, S: Decodable + Send>(addr: Addr) -> Result<(ChannelSender<T>, ChannelReceiver<S>), IoError> {
+    match addr {
+        Addr::Tcp(a) => {
+            let (s, r) = try!(tcp::client_channel(a));
+            Ok((ChannelSender::Tcp(s), ChannelReceiver::Tcp(r)))
+        },
+        Addr::Unix(a) => {
+            let (s, r) = try!(unix::client_channel(a));
+            Ok((ChannelSender::Unix(s), ChannelReceiver::Unix(r)))
+        },
+    }
+}
 
 /// Sender is a generic trait for objects that are able to send values
 /// across a network.
 #[unstable = "waiting for the serialization dust to settle"]
 pub trait Sender<T> where T: Encodable + Send {
     fn send(&mut self, t: T) -> Future<Result<(), SenderError<T>>>;
+
+    /// Like `send`, but also streams the bytes read from `body` to the peer
+    /// as a sequence of chunks immediately following the metadata frame, so
+    /// neither side needs to buffer the whole payload in memory. Transports
+    /// that don't support attached bodies resolve immediately with
+    /// `SenderError::Unsupported`.
+    fn send_with_body(&mut self, _t: T, _body: Box<Reader + Send>) -> Future<Result<(), SenderError<T>>> {
+        let (fi, fo) = mpsc::channel();
+        let _ = fi.send(Err(SenderError::Unsupported));
+        Future::from_receiver(fo)
+    }
 }
 
 #[stable]
@@ -119,6 +209,7 @@ pub enum SenderError<T> {
     #[stable] Mpsc(mpsc::SendError<T>),
     #[stable] Io(IoError),
     #[stable] Encoding(EncodingError),
+    #[stable] Unsupported,
 }
 
 impl<T> Error for SenderError<T> where T: Send {
@@ -128,6 +219,7 @@ impl<T> Error for SenderError<T> where T: Send {
             SenderError::Mpsc(_) => "mpsc error",
             SenderError::Io(_) => "io error",
             SenderError::Encoding(_) => "encoding error",
+            SenderError::Unsupported => "this transport does not support streaming bodies",
         }
     }
 
@@ -136,6 +228,7 @@ impl<T> Error for SenderError<T> where T: Send {
             SenderError::Mpsc(_) => None,
             SenderError::Io(ref err) => Some(err as &Error),
             SenderError::Encoding(ref err) => Some(err as &Error),
+            SenderError::Unsupported => None,
         }
     }
 }
@@ -158,8 +251,16 @@ impl<T> FromError<EncodingError> for SenderError<T> {
     }
 }
 
-/// Contains a type to be sent and a channel for sending the response.
-type SendRequest<T> = (T, mpsc::Sender<Result<(), SenderError<T>>>);
+/// Contains a type to be sent, an optional streaming body to pump
+/// immediately after it, and a channel for sending the response.
+type SendRequest<T> = (T, Option<Box<Reader + Send>>, mpsc::Sender<Result<(), SenderError<T>>>);
+
+/// What the receiving side of a channel observed for an incoming message:
+/// either a plain decoded value, or one with an attached streaming body.
+pub enum Received<S> {
+    Plain(S),
+    WithBody(S, BodyReader),
+}
 
 /// Receiver is a generic trait for objects that are able to receive
 /// values from across a network.
@@ -167,6 +268,14 @@ type SendRequest<T> = (T, mpsc::Sender<Result<(), SenderError<T>>>);
 pub trait Receiver<S> where S: Decodable + Send {
     fn try_recv(&mut self) -> Result<S, ReceiverError<S>>;
 
+    /// Like `try_recv`, but for a message sent via `Sender::send_with_body`:
+    /// returns the decoded metadata together with a `Reader` over the
+    /// streamed body's chunks as they arrive. Transports that don't support
+    /// attached bodies return `ReceiverError::Unsupported`.
+    fn try_recv_with_body(&mut self) -> Result<(S, BodyReader), ReceiverError<S>> {
+        Err(ReceiverError::Unsupported)
+    }
+
     /// Receive a server response. Unlike `try_recv()`, this method panics
     /// if an error is encountered.
     fn recv(&mut self) -> S {
@@ -181,6 +290,7 @@ pub trait Receiver<S> where S: Decodable + Send {
 pub enum ReceiverError<S> {
     #[stable] Io(IoError),
     #[stable] Decoding(DecodingError),
+    #[stable] Unsupported,
 }
 
 impl<S> Error for ReceiverError<S> where S: Send {
@@ -189,6 +299,7 @@ impl<S> Error for ReceiverError<S> where S: Send {
         match *self {
             ReceiverError::Io(_) => "io error",
             ReceiverError::Decoding(_) => "decoding error",
+            ReceiverError::Unsupported => "this transport does not support streaming bodies",
         }
     }
 
@@ -196,10 +307,57 @@ impl<S> Error for ReceiverError<S> where S: Send {
         match *self {
             ReceiverError::Io(ref err) => Some(err as &Error),
             ReceiverError::Decoding(ref err) => Some(err as &Error),
+            ReceiverError::Unsupported => None,
         }
     }
 }
 
+/// Number of bytes read per chunk when pumping a streaming body; also the
+/// buffer size used to pull bytes out of the sender's `Reader`.
+pub const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A `Reader` handed to the receiving side of a message sent with
+/// `Sender::send_with_body`. Yields the body's bytes as its chunks arrive
+/// off the wire, blocking until the next one does, and returns
+/// `EndOfFile` once the sender's end-of-stream marker has been seen.
+pub struct BodyReader {
+    chunks: Option<mpsc::Receiver<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl BodyReader {
+    fn new(chunks: mpsc::Receiver<Vec<u8>>) -> BodyReader {
+        BodyReader { chunks: Some(chunks), buf: Vec::new(), pos: 0 }
+    }
+
+    /// A `BodyReader` that yields no bytes, for callers of
+    /// `try_recv_with_body` who receive a plain message without an
+    /// attached body.
+    fn empty() -> BodyReader {
+        BodyReader { chunks: None, buf: Vec::new(), pos: 0 }
+    }
+}
+
+impl Reader for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.pos >= self.buf.len() {
+            let chunks = match self.chunks {
+                Some(ref chunks) => chunks,
+                None => return Err(IoError { kind: IoErrorKind::EndOfFile, desc: "body stream finished", detail: None }),
+            };
+            match chunks.recv() {
+                Ok(chunk) => { self.buf = chunk; self.pos = 0; },
+                Err(_) => return Err(IoError { kind: IoErrorKind::EndOfFile, desc: "body stream finished", detail: None }),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.buf.len() - self.pos);
+        std::slice::bytes::copy_memory(&mut buf[..n], &self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
 impl<S> FromError<IoError> for ReceiverError<S> {
     fn from_error(err: IoError) -> ReceiverError<S> {
         ReceiverError::Io(err)
@@ -212,18 +370,288 @@ impl<S> FromError<DecodingError> for ReceiverError<S> {
     }
 }
 
-/// Utility method for reading a value from a stream.
-fn read_item<S, R>(r: &mut R, size: usize) -> Result<S, ReceiverError<S>> where S: Decodable, R: Reader {
-    // ???: is it necessary to read the size first if we know what the type is?
-    let data = try!(r.read_exact(size));
-    Ok(try!(decode::<S>(data.as_slice())))
+/// Default cap on a single frame's encoded size, used whenever a caller doesn't
+/// specify their own `SizeLimit`. Chosen to comfortably fit typical messages
+/// while still bounding the allocation a misbehaving peer can trigger.
+pub const DEFAULT_SIZE_LIMIT: u64 = 4 * 1024 * 1024;
+
+/// Default idle interval before a `Ping` is sent to check that a connection
+/// is still alive.
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 15_000;
+
+/// Default amount of time to wait for a `Pong` after a `Ping` before
+/// treating the connection as dead.
+pub const DEFAULT_HEARTBEAT_TIMEOUT_MS: u64 = 5_000;
+
+/// What a server should do when `max_concurrent_clients` connections are
+/// already being handled and another one arrives.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrencyPolicy {
+    /// Accept the new connection but hold it, without firing `on_new` or
+    /// handling it, until a slot frees up; connections arriving meanwhile
+    /// stay unaccepted, so the OS-level backlog does the queuing.
+    Block,
+    /// Accept the connection, then immediately close it.
+    Reject,
+}
+
+/// Tunable behavior shared by every transport's channel constructors.
+///
+/// `max_concurrent_clients` and `concurrency_policy` only affect
+/// `server_channel` and friends; they're ignored by `client_channel`.
+#[derive(Clone, Copy)]
+pub struct ChannelOptions {
+    pub size_limit: SizeLimit,
+    pub heartbeat_interval_ms: u64,
+    pub heartbeat_timeout_ms: u64,
+    pub max_concurrent_clients: Option<usize>,
+    pub concurrency_policy: ConcurrencyPolicy,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> ChannelOptions {
+        ChannelOptions {
+            size_limit: SizeLimit::Bounded(DEFAULT_SIZE_LIMIT),
+            heartbeat_interval_ms: DEFAULT_HEARTBEAT_INTERVAL_MS,
+            heartbeat_timeout_ms: DEFAULT_HEARTBEAT_TIMEOUT_MS,
+            max_concurrent_clients: None,
+            concurrency_policy: ConcurrencyPolicy::Block,
+        }
+    }
+}
+
+/// Discriminates user payload frames from the in-band `Ping`/`Pong`
+/// keepalive control frames, the `Body`/`BodyEnd` frames of a streamed
+/// message body, and the batched `Turn` frame, so all of them can coexist
+/// on the same stream.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum FrameKind {
+    Data,
+    DataWithBody,
+    Body,
+    BodyEnd,
+    Ping,
+    Pong,
+    Turn,
+}
+
+impl FrameKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            FrameKind::Data => 0,
+            FrameKind::DataWithBody => 1,
+            FrameKind::Body => 2,
+            FrameKind::BodyEnd => 3,
+            FrameKind::Ping => 4,
+            FrameKind::Pong => 5,
+            FrameKind::Turn => 6,
+        }
+    }
+
+    fn from_u8(b: u8) -> Option<FrameKind> {
+        match b {
+            0 => Some(FrameKind::Data),
+            1 => Some(FrameKind::DataWithBody),
+            2 => Some(FrameKind::Body),
+            3 => Some(FrameKind::BodyEnd),
+            4 => Some(FrameKind::Ping),
+            5 => Some(FrameKind::Pong),
+            6 => Some(FrameKind::Turn),
+            _ => None,
+        }
+    }
+}
+
+/// What `read_frame` found on the wire.
+pub enum Frame<S> {
+    /// A plain value, sent with `Sender::send`.
+    Data(S),
+    /// A value sent with `Sender::send_with_body`; a sequence of `Body`
+    /// frames carrying the streamed bytes, terminated by `BodyEnd`, follows
+    /// immediately on the same stream.
+    DataWithBody(S),
+    /// One chunk of a streamed body.
+    Body(Vec<u8>),
+    /// Marks the end of a streamed body.
+    BodyEnd,
+    Ping,
+    Pong,
+    /// Several values the sender's writer thread coalesced into a single
+    /// frame, in the order they were sent. Each is delivered to the handler
+    /// individually, as if it had arrived in its own `Data` frame.
+    Turn(Vec<S>),
+}
+
+/// Utility method for reading the next frame from a stream.
+///
+/// The frame kind and length prefix are read first; the length is checked
+/// against `size_limit` before any buffer for the payload is allocated, so
+/// an oversized frame is rejected without the receiver paying for it.
+/// `Ping`/`Pong`/`Body`/`BodyEnd` control frames carry no `S`-typed payload
+/// and are handed back as raw bytes (or not at all) so callers can act on
+/// them without involving `S`.
+fn read_frame<S, R>(r: &mut R, size_limit: SizeLimit) -> Result<Frame<S>, ReceiverError<S>> where S: Decodable, R: Reader {
+    let kind = FrameKind::from_u8(try!(r.read_u8()));
+    let size = try!(r.read_le_uint());
+    if let SizeLimit::Bounded(limit) = size_limit {
+        if size as u64 > limit {
+            // The frame itself is rejected without being decoded, but its
+            // bytes are still on the wire -- drain them now, or the next
+            // `read_frame` call reads the tail of this payload as if it
+            // were a fresh kind/length header and the stream desyncs for
+            // the rest of the connection's life. The drain goes through a
+            // fixed-size scratch buffer so the rejected frame's claimed size
+            // is never allocated.
+            let mut left = size;
+            let mut scratch = [0u8; 4096];
+            while left > 0 {
+                let want = cmp::min(left, scratch.len());
+                try!(r.read_at_least(want, &mut scratch[..want]));
+                left -= want;
+            }
+            return Err(ReceiverError::Decoding(DecodingError::SizeLimit));
+        }
+    }
+    match kind {
+        Some(FrameKind::Ping) => { try!(r.read_exact(size)); Ok(Frame::Ping) },
+        Some(FrameKind::Pong) => { try!(r.read_exact(size)); Ok(Frame::Pong) },
+        Some(FrameKind::Body) => Ok(Frame::Body(try!(r.read_exact(size)))),
+        Some(FrameKind::BodyEnd) => { try!(r.read_exact(size)); Ok(Frame::BodyEnd) },
+        Some(FrameKind::Turn) => {
+            let raw = try!(r.read_exact(size));
+            let mut br = BufReader::new(raw.as_slice());
+            let count = try!(br.read_le_uint());
+            // `count` comes straight off the wire and is otherwise unbounded;
+            // each item needs at least one more length-prefix byte in `raw`,
+            // so capping the reservation at `raw.len()` rules out an attacker
+            // requesting an oversized allocation (`Vec::with_capacity`'s
+            // overflow/OOM path aborts the process, not a catchable panic)
+            // via a tiny frame that just claims a huge `count`.
+            let mut vals = Vec::with_capacity(cmp::min(count, raw.len()));
+            for _ in 0..count {
+                let item_size = try!(br.read_le_uint());
+                if let SizeLimit::Bounded(limit) = size_limit {
+                    if item_size as u64 > limit {
+                        return Err(ReceiverError::Decoding(DecodingError::SizeLimit));
+                    }
+                }
+                let item_data = try!(br.read_exact(item_size));
+                vals.push(try!(decode::<S>(item_data.as_slice())));
+            }
+            Ok(Frame::Turn(vals))
+        },
+        Some(FrameKind::Data) | Some(FrameKind::DataWithBody) | None => {
+            // ???: is it necessary to read the size first if we know what the type is?
+            let data = try!(r.read_exact(size));
+            let val = try!(decode::<S>(data.as_slice()));
+            match kind {
+                Some(FrameKind::DataWithBody) => Ok(Frame::DataWithBody(val)),
+                _ => Ok(Frame::Data(val)),
+            }
+        },
+    }
 }
 
-/// Utility method for writing a value to a stream.
-fn write_item<T, W>(w: &mut W, val: &T) -> Result<(), SenderError<T>> where T: Encodable, W: Writer {
-    let e = try!(encode(val, SizeLimit::Infinite));
-    try!(w.write_le_uint(e.len()));
-    try!(w.write(e.as_slice()));
+/// Utility method for writing a value to a stream as a `Data` (or, if
+/// `kind` is `DataWithBody`, `DataWithBody`) frame.
+///
+/// The value is encoded directly into `w` rather than into an intermediate
+/// `Vec`, so a large `val` only costs one copy instead of two. The length
+/// prefix is computed up front so the receiver can validate it before
+/// allocating anything.
+fn write_item_kind<T, W>(w: &mut W, val: &T, size_limit: SizeLimit, kind: FrameKind) -> Result<(), SenderError<T>> where T: Encodable, W: Writer {
+    let size = bincode::encoded_size(val);
+    if let SizeLimit::Bounded(limit) = size_limit {
+        if size > limit {
+            return Err(SenderError::Encoding(EncodingError::SizeLimit));
+        }
+    }
+    try!(w.write_u8(kind.to_u8()));
+    try!(w.write_le_uint(size as usize));
+    try!(encode_into(val, w, SizeLimit::Bounded(size)));
+    try!(w.flush());
+    Ok(())
+}
+
+fn write_item<T, W>(w: &mut W, val: &T, size_limit: SizeLimit) -> Result<(), SenderError<T>> where T: Encodable, W: Writer {
+    write_item_kind(w, val, size_limit, FrameKind::Data)
+}
+
+/// Write `val` as a `DataWithBody` frame, then pump `body` to `w` as a
+/// sequence of `Body` frames of up to `BODY_CHUNK_SIZE` bytes each,
+/// finishing with an empty `BodyEnd` frame once `body` is exhausted.
+fn write_item_with_body<T, W, R>(w: &mut W, val: &T, size_limit: SizeLimit, body: &mut R) -> Result<(), SenderError<T>>
+        where T: Encodable, W: Writer, R: Reader {
+    try!(write_item_kind(w, val, size_limit, FrameKind::DataWithBody));
+    let mut buf = [0u8; BODY_CHUNK_SIZE];
+    loop {
+        match body.read(&mut buf) {
+            Ok(n) => {
+                try!(w.write_u8(FrameKind::Body.to_u8()));
+                try!(w.write_le_uint(n));
+                try!(w.write(&buf[..n]));
+                try!(w.flush());
+            },
+            Err(ref e) if e.kind == IoErrorKind::EndOfFile => break,
+            Err(e) => return Err(SenderError::Io(e)),
+        }
+    }
+    try!(w.write_u8(FrameKind::BodyEnd.to_u8()));
+    try!(w.write_le_uint(0));
     try!(w.flush());
     Ok(())
 }
+
+/// Write `vals` as a single `Turn` frame: a count-prefixed sequence of
+/// length-prefixed encoded values, written and flushed together so a burst
+/// of sends costs one syscall instead of one per value. Falls back to a
+/// plain `Data` frame when `vals` holds exactly one value, since there's no
+/// syscall to amortize and the receiver can skip straight to `Frame::Data`.
+fn write_turn<T, W>(w: &mut W, vals: &[T], size_limit: SizeLimit) -> Result<(), SenderError<T>> where T: Encodable, W: Writer {
+    if vals.len() == 1 {
+        return write_item(w, &vals[0], size_limit);
+    }
+    let mut payload = MemWriter::new();
+    try!(payload.write_le_uint(vals.len()));
+    for val in vals.iter() {
+        let size = bincode::encoded_size(val);
+        if let SizeLimit::Bounded(limit) = size_limit {
+            if size > limit {
+                return Err(SenderError::Encoding(EncodingError::SizeLimit));
+            }
+        }
+        try!(payload.write_le_uint(size as usize));
+        try!(encode_into(val, &mut payload, SizeLimit::Bounded(size)));
+    }
+    let payload = payload.into_inner();
+    // Each item fit within `size_limit` on its own, but the `Turn` frame
+    // they're bundled into is itself subject to the same limit -- check the
+    // assembled payload before anything reaches `w`, or a burst of
+    // individually-valid sends could write an oversized frame that the
+    // receiving `read_frame` then rejects out from under every future this
+    // batch is about to resolve as `Ok`.
+    if let SizeLimit::Bounded(limit) = size_limit {
+        if payload.len() as u64 > limit {
+            return Err(SenderError::Encoding(EncodingError::SizeLimit));
+        }
+    }
+    try!(w.write_u8(FrameKind::Turn.to_u8()));
+    try!(w.write_le_uint(payload.len()));
+    try!(w.write(payload.as_slice()));
+    try!(w.flush());
+    Ok(())
+}
+
+/// Write an empty `Ping` control frame.
+fn write_ping<W: Writer>(w: &mut W) -> Result<(), IoError> {
+    try!(w.write_u8(FrameKind::Ping.to_u8()));
+    try!(w.write_le_uint(0));
+    w.flush()
+}
+
+/// Write an empty `Pong` control frame, sent in reply to a `Ping`.
+fn write_pong<W: Writer>(w: &mut W) -> Result<(), IoError> {
+    try!(w.write_u8(FrameKind::Pong.to_u8()));
+    try!(w.write_le_uint(0));
+    w.flush()
+}
@@ -0,0 +1,390 @@
+//! Generic stream plumbing shared by the `tcp` and `unix` transports.
+//!
+//! Both transports frame messages, spawn reader/writer threads, and run the
+//! `Ping`/`Pong` keepalive in exactly the same way; the only thing that
+//! differs between them is how the underlying stream is obtained
+//! (`TcpStream` vs `UnixStream`). Keeping that shared logic here means
+//! `tcp.rs` and `unix.rs` only have to deal with their own connection
+//! setup, not re-implement the framing and heartbeat loops.
+
+use bincode::{EncodingError, SizeLimit};
+use rustc_serialize::{Decodable, Encodable};
+use std::collections::ring_buf::RingBuf;
+use std::error::Error;
+use std::io::{IoErrorKind, Reader, Writer};
+use std::io::timer::Timer;
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::thread::Thread;
+use std::time::duration::Duration;
+use time;
+use super::{BodyReader, ChannelOptions, Frame, IoError, Received, ReceiverError, SendRequest, SenderError};
+
+/// Timestamp (in nanoseconds) of the last frame of any kind -- a value, a
+/// body chunk, a `Ping`, or a `Pong` -- seen from a peer. Shared between a connection's reader and its
+/// heartbeat thread so the heartbeat can tell whether anything proved the
+/// peer was still alive since the last `Ping` it sent.
+type Liveness = Arc<Mutex<u64>>;
+
+fn new_liveness() -> Liveness {
+    Arc::new(Mutex::new(time::precise_time_ns()))
+}
+
+fn touch(liveness: &Liveness) {
+    *liveness.lock().unwrap() = time::precise_time_ns();
+}
+
+/// An `IoError` synthesized locally when a peer misses a `Pong` within the
+/// configured heartbeat timeout; nothing was actually read off the wire.
+fn dead_peer_error() -> IoError {
+    IoError { kind: IoErrorKind::TimedOut, desc: "peer missed heartbeat Pong", detail: None }
+}
+
+/// Spawn the thread that sends a `Ping` after every idle period and treats
+/// a missed `Pong` as a dead connection, invoking `on_dead` exactly once.
+fn spawn_heartbeat<C, F>(mut stream: C, liveness: Liveness, interval_ms: u64, timeout_ms: u64, mut on_dead: F)
+        where C: Writer + Send, F: FnMut() + Send {
+    Thread::spawn(move || {
+        let mut timer = Timer::new().unwrap();
+        loop {
+            timer.sleep(Duration::milliseconds(interval_ms as i64));
+            let sent_at = time::precise_time_ns();
+            if super::write_ping(&mut stream).is_err() {
+                on_dead();
+                return;
+            }
+            timer.sleep(Duration::milliseconds(timeout_ms as i64));
+            if *liveness.lock().unwrap() < sent_at {
+                on_dead();
+                return;
+            }
+        }
+    });
+}
+
+/// Spawn the writer thread for a client connection: pulls `SendRequest`s off
+/// `sr` and writes them to `stream`, pumping an attached body (if any)
+/// right after its metadata frame, and resolving each request's future with
+/// the outcome.
+///
+/// Requests without a body that are already queued up together are
+/// coalesced into a single `Turn` frame instead of one frame each, so a
+/// burst of sends costs one write-and-flush instead of one per send. A
+/// request with a body always ends its batch -- and starts the next one --
+/// since its streamed bytes can't be folded into a `Turn`'s framing.
+pub fn spawn_client_writer<C, T>(mut stream: C, sr: mpsc::Receiver<SendRequest<T>>, size_limit: SizeLimit)
+        where C: Reader + Writer + Send, T: Encodable + Send {
+    Thread::spawn(move || {
+        loop {
+            let (t, body, fi) = match sr.recv() {
+                Ok(req) => req,
+                Err(_) => return,
+            };
+            if body.is_some() {
+                let result = super::write_item_with_body(&mut stream, &t, size_limit, &mut *body.unwrap());
+                fi.send(result);
+                continue;
+            }
+            let mut batch = vec![t];
+            let mut waiting = vec![fi];
+            loop {
+                match sr.try_recv() {
+                    Ok((t, None, fi)) => { batch.push(t); waiting.push(fi); },
+                    Ok((t, Some(body), fi)) => {
+                        // Flush what's queued so far as its own turn before
+                        // handling the body-bearing request, which can't be
+                        // folded into the same frame.
+                        let result = super::write_turn(&mut stream, batch.as_slice(), size_limit);
+                        for fi in waiting.into_iter() {
+                            fi.send(clone_result(&result));
+                        }
+                        let result = super::write_item_with_body(&mut stream, &t, size_limit, &mut *body);
+                        fi.send(result);
+                        batch = Vec::new();
+                        waiting = Vec::new();
+                    },
+                    Err(_) => break,
+                }
+            }
+            if !batch.is_empty() {
+                let result = super::write_turn(&mut stream, batch.as_slice(), size_limit);
+                for fi in waiting.into_iter() {
+                    fi.send(clone_result(&result));
+                }
+            }
+        }
+    });
+}
+
+/// `SenderError<T>` isn't `Clone` (its `Mpsc` variant holds the unsent `T`,
+/// which isn't bound to `Clone`), so a single `write_turn` outcome is
+/// turned into one result per batched request by hand. `write_turn` never
+/// produces `SenderError::Mpsc` -- that only comes from a transport's own
+/// `send`/`send_with_body` -- so every request in the batch either all
+/// succeeded together or all failed with the same kind of io/encoding
+/// error; which specific encoding variant it was doesn't need to survive
+/// the copy, since every caller just matches on `SenderError::Encoding(_)`.
+fn clone_result<T>(result: &Result<(), SenderError<T>>) -> Result<(), SenderError<T>> {
+    match *result {
+        Ok(()) => Ok(()),
+        Err(SenderError::Io(ref e)) => Err(SenderError::Io(IoError { kind: e.kind, desc: e.desc, detail: None })),
+        Err(SenderError::Encoding(_)) => Err(SenderError::Encoding(EncodingError::SizeLimit)),
+        Err(SenderError::Unsupported) => Err(SenderError::Unsupported),
+        Err(SenderError::Mpsc(_)) => unreachable!("write_turn never produces SenderError::Mpsc"),
+    }
+}
+
+/// Spawn the reader thread for a client connection: reads framed values off
+/// `stream` and forwards them (or any error) to `rs`. Also answers `Ping`s
+/// with a `Pong` and runs the heartbeat that watches for a silent peer,
+/// surfacing a `ReceiverError::Io` on `rs` if one is detected.
+///
+/// A `DataWithBody` frame hands the decoded value to `rs` immediately,
+/// paired with a `BodyReader` that this thread keeps feeding with `Body`
+/// frames -- pausing the normal frame loop -- until the matching `BodyEnd`
+/// arrives.
+pub fn spawn_client_reader<C, S>(stream: C, rs: mpsc::Sender<Result<Received<S>, ReceiverError<S>>>, options: ChannelOptions)
+        where C: Reader + Writer + Clone + Send, S: Decodable + Send {
+    let liveness = new_liveness();
+    {
+        let ping_stream = stream.clone();
+        let rs = rs.clone();
+        let liveness = liveness.clone();
+        spawn_heartbeat(ping_stream, liveness, options.heartbeat_interval_ms, options.heartbeat_timeout_ms, move || {
+            let _ = rs.send(Err(ReceiverError::Io(dead_peer_error())));
+        });
+    }
+    let mut stream = stream;
+    Thread::spawn(move || {
+        loop {
+            match super::read_frame::<S, C>(&mut stream, options.size_limit) {
+                Err(ReceiverError::Io(ref e)) if e.kind == IoErrorKind::TimedOut => (),
+                Err(ReceiverError::Io(ref e)) if e.kind == IoErrorKind::EndOfFile => return,
+                Err(e) => match rs.send(Err(e)) {
+                    Ok(_) => (),
+                    Err(e) => panic!("{:?}", e),
+                },
+                Ok(Frame::Pong) => touch(&liveness),
+                Ok(Frame::Ping) => {
+                    touch(&liveness);
+                    let _ = super::write_pong(&mut stream);
+                },
+                Ok(Frame::Data(val)) => {
+                    touch(&liveness);
+                    match rs.send(Ok(Received::Plain(val))) {
+                        Ok(_) => (),
+                        Err(e) => panic!("{:?}", e),
+                    }
+                },
+                Ok(Frame::DataWithBody(val)) => {
+                    touch(&liveness);
+                    let (btx, brx) = mpsc::channel::<Vec<u8>>();
+                    if rs.send(Ok(Received::WithBody(val, BodyReader::new(brx)))).is_err() {
+                        return;
+                    }
+                    if !pump_body::<C, S>(&mut stream, &liveness, options.size_limit, &btx) {
+                        return;
+                    }
+                },
+                Ok(Frame::Turn(vals)) => {
+                    touch(&liveness);
+                    for val in vals.into_iter() {
+                        if rs.send(Ok(Received::Plain(val))).is_err() {
+                            return;
+                        }
+                    }
+                },
+                Ok(Frame::Body(_)) | Ok(Frame::BodyEnd) => {
+                    // A body frame arriving outside of `pump_body` means the
+                    // peer violated the protocol; there's no sane value to
+                    // hand to `rs` so just drop the connection.
+                    return;
+                },
+            }
+        }
+    });
+}
+
+/// Read `Body` frames off `stream`, forwarding each chunk to `btx`, until the
+/// matching `BodyEnd` arrives. Returns `false` if the stream ended or erred
+/// before that happened, in which case the caller should stop reading.
+/// `btx`'s receiver may have already been dropped by a caller uninterested
+/// in the body; chunks are still drained off the wire so the stream stays
+/// in sync, they're just not delivered anywhere.
+fn pump_body<C, S>(stream: &mut C, liveness: &Liveness, size_limit: SizeLimit, btx: &mpsc::Sender<Vec<u8>>) -> bool
+        where C: Reader + Writer, S: Decodable {
+    loop {
+        match super::read_frame::<S, C>(stream, size_limit) {
+            Ok(Frame::Body(chunk)) => {
+                touch(liveness);
+                let _ = btx.send(chunk);
+            },
+            Ok(Frame::BodyEnd) => {
+                touch(liveness);
+                return true;
+            },
+            Ok(Frame::Pong) => touch(liveness),
+            Ok(Frame::Ping) => {
+                touch(liveness);
+                let _ = super::write_pong(stream);
+            },
+            Err(ReceiverError::Io(ref e)) if e.kind == IoErrorKind::TimedOut => continue,
+            _ => return false,
+        }
+    }
+}
+
+/// A counting semaphore used to cap how many client connections
+/// `server_channel` hands off to a handler thread at once.
+///
+/// `acquire` blocks until a permit is free, for `ConcurrencyPolicy::Block`;
+/// `try_acquire` never blocks and reports whether a permit was taken, for
+/// `ConcurrencyPolicy::Reject`. `release` must be called exactly once per
+/// successful acquire, which `fire_drop_once` takes care of alongside
+/// recycling the client id.
+pub struct Semaphore {
+    held: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+impl Semaphore {
+    pub fn new(max: usize) -> Semaphore {
+        Semaphore { held: Mutex::new(0), freed: Condvar::new(), max: max }
+    }
+
+    pub fn acquire(&self) {
+        let mut held = self.held.lock().unwrap();
+        while *held >= self.max {
+            held = self.freed.wait(held).unwrap();
+        }
+        *held += 1;
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut held = self.held.lock().unwrap();
+        if *held >= self.max {
+            false
+        } else {
+            *held += 1;
+            true
+        }
+    }
+
+    pub fn release(&self) {
+        *self.held.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+}
+
+/// Recycle `client_id`, release its slot in `limiter` (if the server is
+/// bounding concurrency), and fire `on_drop`, but only the first time this
+/// is called for a given connection -- both the reader loop (on
+/// `EndOfFile`) and the heartbeat (on a missed `Pong`) can independently
+/// notice the disconnect, and `on_drop` should only fire once.
+fn fire_drop_once<D>(dropped: &Arc<Mutex<bool>>, freed_clients: &Arc<Mutex<RingBuf<u32>>>,
+                      limiter: &Option<Arc<Semaphore>>, client_id: u32, on_drop: D)
+        where D: Fn(u32) -> () {
+    let mut fired = dropped.lock().unwrap();
+    if !*fired {
+        *fired = true;
+        freed_clients.lock().unwrap().push_back(client_id.clone());
+        if let Some(ref limiter) = *limiter {
+            limiter.release();
+        }
+        on_drop(client_id);
+    }
+}
+
+/// Releases a connection's semaphore permit and recycles its `client_id` by
+/// calling `fire_drop_once` when dropped, so a panic inside caller-supplied
+/// code (`on_msg`) unwinding the handler thread can't leak either one --
+/// only explicit `return`s or the ordinary end of the handler's closure
+/// would otherwise run that cleanup, and a panic skips straight past those.
+struct ConnectionGuard<D> where D: Fn(u32) -> () + Copy {
+    dropped: Arc<Mutex<bool>>,
+    freed_clients: Arc<Mutex<RingBuf<u32>>>,
+    limiter: Option<Arc<Semaphore>>,
+    client_id: u32,
+    on_drop: D,
+}
+
+impl<D> Drop for ConnectionGuard<D> where D: Fn(u32) -> () + Copy {
+    fn drop(&mut self) {
+        fire_drop_once(&self.dropped, &self.freed_clients, &self.limiter, self.client_id, self.on_drop);
+    }
+}
+
+/// Spawn the per-connection handler loop on the server side: reads framed
+/// requests off `conn`, hands each one to `on_msg`, and writes the response
+/// back. Answers `Ping`s with a `Pong`, and runs the heartbeat that treats a
+/// silent peer as a disconnect. Recycles `client_id` into `freed_clients`,
+/// releases `limiter` (if the server is bounding concurrency), and fires
+/// `on_drop` exactly once, however the disconnect was detected -- including
+/// a panic inside `on_msg`, via `ConnectionGuard`.
+pub fn spawn_server_connection<C, T, S, H, D>(conn: C, client_id: u32, on_msg: H, on_drop: D,
+                                               freed_clients: Arc<Mutex<RingBuf<u32>>>,
+                                               limiter: Option<Arc<Semaphore>>, options: ChannelOptions)
+        where C: Reader + Writer + Clone + Send,
+              T: Encodable + Send,
+              S: Decodable + Send,
+              H: Fn(u32, S) -> T + Copy + Send,
+              D: Fn(u32) -> () + Copy + Send,
+{
+    let liveness = new_liveness();
+    let dropped = Arc::new(Mutex::new(false));
+    {
+        let ping_stream = conn.clone();
+        let liveness = liveness.clone();
+        let freed_clients = freed_clients.clone();
+        let limiter = limiter.clone();
+        let dropped = dropped.clone();
+        spawn_heartbeat(ping_stream, liveness, options.heartbeat_interval_ms, options.heartbeat_timeout_ms, move || {
+            fire_drop_once(&dropped, &freed_clients, &limiter, client_id, on_drop);
+        });
+    }
+    let mut conn = conn;
+    Thread::spawn(move || {
+        let _guard = ConnectionGuard {
+            dropped: dropped, freed_clients: freed_clients, limiter: limiter,
+            client_id: client_id, on_drop: on_drop,
+        };
+        loop {
+            // Collects one item per `Data` frame, or all of them (in order)
+            // per `Turn` frame, so a batched request is dispatched to
+            // `on_msg` exactly like a run of individual ones would be.
+            let items = match super::read_frame::<S, C>(&mut conn, options.size_limit) {
+                Err(ReceiverError::Io(ref e)) if e.kind == IoErrorKind::TimedOut => continue,
+                Err(ReceiverError::Io(ref e)) if e.kind == IoErrorKind::EndOfFile => return,
+                Err(e) => panic!("{:?}", e.description()),
+                Ok(Frame::Pong) => { touch(&liveness); continue; },
+                Ok(Frame::Ping) => {
+                    touch(&liveness);
+                    let _ = super::write_pong(&mut conn);
+                    continue;
+                },
+                Ok(Frame::Data(val)) => { touch(&liveness); vec![val] },
+                Ok(Frame::Turn(vals)) => { touch(&liveness); vals },
+                Ok(Frame::DataWithBody(val)) => {
+                    // `on_msg` has no way to receive a body yet, so drain it
+                    // off the wire to keep the stream in sync and dispatch
+                    // on the metadata alone.
+                    touch(&liveness);
+                    let (btx, _) = mpsc::channel::<Vec<u8>>();
+                    if !pump_body::<C, S>(&mut conn, &liveness, options.size_limit, &btx) {
+                        return;
+                    }
+                    vec![val]
+                },
+                Ok(Frame::Body(_)) | Ok(Frame::BodyEnd) => {
+                    // Arrived outside of `pump_body`, i.e. the peer violated
+                    // the protocol; there's nothing sane to dispatch.
+                    return;
+                },
+            };
+            for item in items.into_iter() {
+                let resp = on_msg(client_id, item);
+                super::write_item(&mut conn, &resp, options.size_limit);
+            }
+        }
+    });
+}
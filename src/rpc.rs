@@ -0,0 +1,439 @@
+#![unstable]
+//! Module `rpc` multiplexes several named request/response endpoints over a
+//! single TCP connection.
+//!
+//! Every frame is prefixed with a small header -- a `u8` priority followed
+//! by a length-delimited endpoint name -- ahead of the usual length-prefixed
+//! bincode payload used elsewhere in this crate. On the server side, a
+//! registry of handlers (each with its own decode type and response type)
+//! is dispatched by endpoint name; on the client side, `Client::endpoint`
+//! hands out a typed `Sender`/`Receiver` pair per endpoint, all sharing the
+//! one underlying connection. Responses carry the same priority byte they
+//! were requested with, so the per-connection writer thread can drain a
+//! small priority queue and let urgent responses jump ahead of buffered
+//! bulk traffic.
+
+use bincode::{decode, encode_into, encoded_size, EncodingError, SizeLimit};
+use rustc_serialize::{Decodable, Encodable};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::collections::ring_buf::RingBuf;
+use std::error::Error;
+use std::io::{Acceptor, IoError, IoErrorKind, IoResult, Listener, MemWriter, Reader, TcpStream, Writer};
+use std::io::net::ip::ToSocketAddr;
+use std::io::net::tcp::TcpListener;
+use std::marker::PhantomData;
+use std::sync::{Arc, Future, Mutex, mpsc};
+use std::thread::Thread;
+use super::{ReceiverError, SenderError, Sender, Receiver};
+
+/// A frame's priority: within a connection's write queue, higher values are
+/// drained first.
+pub type Priority = u8;
+
+pub const PRIORITY_NORMAL: Priority = 0;
+pub const PRIORITY_HIGH: Priority = 255;
+
+fn write_header<W: Writer>(w: &mut W, priority: Priority, endpoint: &str) -> IoResult<()> {
+    let name = endpoint.as_bytes();
+    try!(w.write_u8(priority));
+    try!(w.write_u8(name.len() as u8));
+    try!(w.write(name));
+    Ok(())
+}
+
+fn read_header<R: Reader>(r: &mut R) -> IoResult<(Priority, String)> {
+    let priority = try!(r.read_u8());
+    let len = try!(r.read_u8()) as usize;
+    let name = try!(r.read_exact(len));
+    Ok((priority, String::from_utf8_lossy(name.as_slice()).into_owned()))
+}
+
+fn write_raw<W: Writer>(w: &mut W, data: &[u8]) -> IoResult<()> {
+    try!(w.write_le_uint(data.len()));
+    w.write(data)
+}
+
+fn read_raw<R: Reader>(r: &mut R, size_limit: SizeLimit) -> IoResult<Vec<u8>> {
+    let size = try!(r.read_le_uint());
+    if let SizeLimit::Bounded(limit) = size_limit {
+        if size as u64 > limit {
+            // The bytes are still on the wire even though the frame is
+            // rejected -- drain them now, or the next `read_raw` call reads
+            // the tail of this payload as if it were a fresh header and the
+            // stream desyncs for the rest of the connection's life.
+            try!(r.read_exact(size));
+            return Err(IoError { kind: IoErrorKind::InvalidInput, desc: "frame exceeds configured size limit", detail: None });
+        }
+    }
+    r.read_exact(size)
+}
+
+/// A frame waiting to be written by a connection's writer thread.
+///
+/// `on_result` is called with the outcome of the write so the caller who
+/// enqueued it can resolve their `Future`; it's boxed because a single
+/// writer thread drains frames from every endpoint multiplexed over the
+/// connection, each with its own response type.
+struct QueuedFrame {
+    priority: Priority,
+    seq: u64,
+    endpoint: String,
+    payload: Vec<u8>,
+    on_result: Box<FnMut(IoResult<()>) + Send>,
+}
+
+impl PartialEq for QueuedFrame {
+    fn eq(&self, other: &QueuedFrame) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl Eq for QueuedFrame {}
+
+impl PartialOrd for QueuedFrame {
+    fn partial_cmp(&self, other: &QueuedFrame) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedFrame {
+    fn cmp(&self, other: &QueuedFrame) -> Ordering {
+        // Higher priority drains first; among equal priorities, the lower
+        // (earlier) sequence number drains first so same-priority frames
+        // stay in FIFO order.
+        match self.priority.cmp(&other.priority) {
+            Ordering::Equal => other.seq.cmp(&self.seq),
+            ord => ord,
+        }
+    }
+}
+
+/// Spawn the per-connection writer thread: buffers frames from `rx` in a
+/// priority queue and writes them out highest-priority-first, whenever the
+/// queue empties it blocks for the next frame.
+fn spawn_writer<W: Writer + Send>(mut stream: W, rx: mpsc::Receiver<QueuedFrame>) {
+    Thread::spawn(move || {
+        let mut heap: BinaryHeap<QueuedFrame> = BinaryHeap::new();
+        loop {
+            let mut frame = match heap.pop() {
+                Some(frame) => frame,
+                None => match rx.recv() {
+                    Ok(frame) => frame,
+                    Err(_) => return,
+                },
+            };
+            let result = write_header(&mut stream, frame.priority, &frame.endpoint)
+                .and_then(|_| write_raw(&mut stream, frame.payload.as_slice()))
+                .and_then(|_| stream.flush());
+            let failed = result.is_err();
+            (frame.on_result)(result);
+            if failed {
+                return;
+            }
+            // Pull in anything else that's immediately available so several
+            // buffered frames get priority-ordered together rather than
+            // written out strictly in arrival order.
+            loop {
+                match rx.try_recv() {
+                    Ok(frame) => heap.push(frame),
+                    Err(_) => break,
+                }
+            }
+        }
+    });
+}
+
+/// A handler for a single named endpoint, decoupled from its concrete
+/// request/response types so a registry of them can be dispatched through
+/// at runtime.
+trait Endpoint: Send + Sync {
+    fn handle(&self, client_id: u32, payload: &[u8], size_limit: SizeLimit) -> Result<Vec<u8>, Box<Error>>;
+}
+
+struct TypedEndpoint<S, T, H> {
+    handler: H,
+    _marker: PhantomData<(S, T)>,
+}
+
+impl<S, T, H> Endpoint for TypedEndpoint<S, T, H>
+        where S: Decodable, T: Encodable, H: Fn(u32, S) -> T + Send + Sync {
+    fn handle(&self, client_id: u32, payload: &[u8], size_limit: SizeLimit) -> Result<Vec<u8>, Box<Error>> {
+        let req: S = try!(decode(payload));
+        let resp = (self.handler)(client_id, req);
+        let size = encoded_size(&resp);
+        if let SizeLimit::Bounded(limit) = size_limit {
+            if size > limit {
+                return Err(Box::new(EncodingError::SizeLimit));
+            }
+        }
+        let mut w = MemWriter::new();
+        try!(encode_into(&resp, &mut w, SizeLimit::Bounded(size)));
+        Ok(w.into_inner())
+    }
+}
+
+/// Maps endpoint names to the handlers that serve them. Built with
+/// `register` and handed to `server_channel`.
+pub struct EndpointRegistry {
+    handlers: HashMap<String, Box<Endpoint>>,
+}
+
+impl EndpointRegistry {
+    pub fn new() -> EndpointRegistry {
+        EndpointRegistry { handlers: HashMap::new() }
+    }
+
+    /// Register a handler for `name`. Each endpoint has its own request
+    /// type `S` and response type `T`; incoming frames addressed to `name`
+    /// are decoded and dispatched to `handler`.
+    pub fn register<S, T, H>(&mut self, name: &str, handler: H)
+            where S: Decodable + Send + 'static,
+                  T: Encodable + Send + 'static,
+                  H: Fn(u32, S) -> T + Send + Sync + 'static {
+        self.handlers.insert(name.to_string(), Box::new(TypedEndpoint {
+            handler: handler,
+            _marker: PhantomData,
+        }));
+    }
+}
+
+/// Recycles a connection's `client_id` into `freed_clients` and fires
+/// `on_drop` when dropped, so a panic on attacker-controlled input (a bad
+/// header, an oversized frame, a decode failure, an unknown endpoint name)
+/// unwinding `spawn_connection`'s handler thread can't leak the client slot
+/// or skip the disconnect notification the way a bare `panic!` would --
+/// mirrors `net::ConnectionGuard`.
+struct ConnectionGuard<D> where D: Fn(u32) -> () + Copy {
+    freed_clients: Arc<Mutex<RingBuf<u32>>>,
+    client_id: u32,
+    on_drop: D,
+}
+
+impl<D> Drop for ConnectionGuard<D> where D: Fn(u32) -> () + Copy {
+    fn drop(&mut self) {
+        self.freed_clients.lock().unwrap().push_back(self.client_id.clone());
+        (self.on_drop)(self.client_id);
+    }
+}
+
+fn spawn_connection<D>(mut conn: TcpStream, client_id: u32, registry: Arc<HashMap<String, Box<Endpoint>>>,
+                        on_drop: D, freed_clients: Arc<Mutex<RingBuf<u32>>>, size_limit: SizeLimit)
+        where D: Fn(u32) -> () + Copy + Send {
+    let (tx, rx) = mpsc::channel::<QueuedFrame>();
+    spawn_writer(conn.clone(), rx);
+    Thread::spawn(move || {
+        let _guard = ConnectionGuard { freed_clients: freed_clients, client_id: client_id, on_drop: on_drop };
+        let mut seq = 0u64;
+        loop {
+            let (priority, name) = match read_header(&mut conn) {
+                Ok(h) => h,
+                Err(ref e) if e.kind == IoErrorKind::EndOfFile => return,
+                Err(e) => panic!("{}", e),
+            };
+            let payload = match read_raw(&mut conn, size_limit) {
+                Ok(p) => p,
+                Err(e) => panic!("{}", e),
+            };
+            match registry.get(&name) {
+                Some(endpoint) => match endpoint.handle(client_id, payload.as_slice(), size_limit) {
+                    Ok(resp) => {
+                        seq += 1;
+                        let frame = QueuedFrame {
+                            priority: priority,
+                            seq: seq,
+                            endpoint: name,
+                            payload: resp,
+                            on_result: Box::new(|_: IoResult<()>| ()),
+                        };
+                        if tx.send(frame).is_err() {
+                            return;
+                        }
+                    },
+                    Err(e) => panic!("{}", e),
+                },
+                None => panic!("no endpoint registered for {:?}", name),
+            }
+        }
+    });
+}
+
+/// Listen for incoming connections and dispatch each framed request to the
+/// handler registered for its endpoint.
+///
+///  * `on_new`: notification of a new client connection
+///  * `on_drop`: notification of a client hanging up
+#[allow(unused_must_use)]
+pub fn server_channel<A, N, D>(addr: A, registry: EndpointRegistry, on_new: N, on_drop: D, size_limit: SizeLimit) -> Result<(), Box<Error>>
+        where A: ToSocketAddr,
+              N: Fn(u32) -> () + Copy + Send,
+              D: Fn(u32) -> () + Copy + Send {
+    let listener = try!(TcpListener::bind(addr));
+    let acceptor = try!(listener.listen());
+    let registry = Arc::new(registry.handlers);
+    {
+        let mut acceptor = acceptor.clone();
+        let mut client_counter = 0;
+        let freed_clients = Arc::new(Mutex::new(RingBuf::new()));
+        for conn in acceptor.incoming() {
+            match conn {
+                Ok(conn) => {
+                    let client_id = match freed_clients.lock().unwrap().pop_front() {
+                        Some(id) => id,
+                        None => {
+                            client_counter = client_counter + 1;
+                            client_counter
+                        },
+                    };
+                    on_new(client_id);
+                    spawn_connection(conn, client_id, registry.clone(), on_drop, freed_clients.clone(), size_limit);
+                },
+                Err(ref e) if e.kind == IoErrorKind::EndOfFile => break,
+                Err(e) => panic!(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Demuxes decoded response bytes for a single endpoint into the mpsc
+/// channel that feeds its `EndpointHandle::recv()`.
+trait ResponseRoute: Send {
+    fn route(&self, payload: &[u8]);
+}
+
+struct TypedRoute<S> {
+    tx: mpsc::Sender<Result<S, ReceiverError<S>>>,
+}
+
+impl<S> ResponseRoute for TypedRoute<S> where S: Decodable + Send {
+    fn route(&self, payload: &[u8]) {
+        let result = match decode::<S>(payload) {
+            Ok(val) => Ok(val),
+            Err(e) => Err(ReceiverError::Decoding(e)),
+        };
+        let _ = self.tx.send(result);
+    }
+}
+
+/// A connection shared by every endpoint handle created from it.
+pub struct Client {
+    write_tx: mpsc::Sender<QueuedFrame>,
+    routes: Arc<Mutex<HashMap<String, Box<ResponseRoute>>>>,
+    seq: Arc<Mutex<u64>>,
+    size_limit: SizeLimit,
+}
+
+impl Client {
+    /// Connect to an RPC server at `addr`.
+    pub fn connect<A: ToSocketAddr>(addr: A, size_limit: SizeLimit) -> IoResult<Client> {
+        let stream = try!(TcpStream::connect(addr));
+        let (tx, rx) = mpsc::channel::<QueuedFrame>();
+        spawn_writer(stream.clone(), rx);
+
+        let routes: Arc<Mutex<HashMap<String, Box<ResponseRoute>>>> = Arc::new(Mutex::new(HashMap::new()));
+        {
+            let mut stream = stream.clone();
+            let routes = routes.clone();
+            Thread::spawn(move || {
+                loop {
+                    let (_, name) = match read_header(&mut stream) {
+                        Ok(h) => h,
+                        Err(_) => return,
+                    };
+                    let payload = match read_raw(&mut stream, size_limit) {
+                        Ok(p) => p,
+                        Err(_) => return,
+                    };
+                    if let Some(route) = routes.lock().unwrap().get(&name) {
+                        route.route(payload.as_slice());
+                    }
+                }
+            });
+        }
+
+        Ok(Client { write_tx: tx, routes: routes, seq: Arc::new(Mutex::new(0)), size_limit: size_limit })
+    }
+
+    /// Build a typed handle for endpoint `name`. Sends issued through the
+    /// handle are tagged with `priority`, which the server echoes back on
+    /// its response so this connection's writer thread can order it
+    /// accordingly too.
+    pub fn endpoint<T, S>(&self, name: &str, priority: Priority) -> EndpointHandle<T, S>
+            where T: Encodable + Send, S: Decodable + Send + 'static {
+        let (rtx, rrx) = mpsc::channel::<Result<S, ReceiverError<S>>>();
+        self.routes.lock().unwrap().insert(name.to_string(), Box::new(TypedRoute { tx: rtx }));
+        EndpointHandle {
+            name: name.to_string(),
+            priority: priority,
+            write_tx: self.write_tx.clone(),
+            seq: self.seq.clone(),
+            size_limit: self.size_limit,
+            response_rx: rrx,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// A typed `Sender`/`Receiver` pair bound to one named endpoint of a
+/// `Client`'s shared connection.
+pub struct EndpointHandle<T, S> where T: Encodable + Send, S: Decodable + Send {
+    name: String,
+    priority: Priority,
+    write_tx: mpsc::Sender<QueuedFrame>,
+    seq: Arc<Mutex<u64>>,
+    size_limit: SizeLimit,
+    response_rx: mpsc::Receiver<Result<S, ReceiverError<S>>>,
+    _marker: PhantomData<T>,
+}
+
+impl<T, S> Sender<T> for EndpointHandle<T, S> where T: Encodable + Send, S: Decodable + Send {
+    fn send(&mut self, t: T) -> Future<Result<(), SenderError<T>>> {
+        let (fi, fo) = mpsc::channel();
+        let size = encoded_size(&t);
+        if let SizeLimit::Bounded(limit) = self.size_limit {
+            if size > limit {
+                fi.send(Err(SenderError::Encoding(EncodingError::SizeLimit)));
+                return Future::from_receiver(fo);
+            }
+        }
+        let mut w = MemWriter::new();
+        let payload = match encode_into(&t, &mut w, SizeLimit::Bounded(size)) {
+            Ok(()) => w.into_inner(),
+            Err(e) => {
+                fi.send(Err(SenderError::Encoding(e)));
+                return Future::from_receiver(fo);
+            },
+        };
+        let seq = {
+            let mut seq = self.seq.lock().unwrap();
+            *seq += 1;
+            *seq
+        };
+        let frame = QueuedFrame {
+            priority: self.priority,
+            seq: seq,
+            endpoint: self.name.clone(),
+            payload: payload,
+            on_result: Box::new(move |r: IoResult<()>| {
+                let _ = fi.send(match r {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(SenderError::Io(e)),
+                });
+            }),
+        };
+        if self.write_tx.send(frame).is_err() {
+            panic!("can't send, writer thread hung up");
+        }
+        Future::from_receiver(fo)
+    }
+}
+
+impl<T, S> Receiver<S> for EndpointHandle<T, S> where T: Encodable + Send, S: Decodable + Send {
+    fn try_recv(&mut self) -> Result<S, ReceiverError<S>> {
+        match self.response_rx.recv() {
+            Ok(x) => x,
+            Err(_) => panic!("writer/reader thread hung up!"),
+        }
+    }
+}
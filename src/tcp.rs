@@ -2,15 +2,17 @@
 //! Module `tcp` provides support for channels that communicate
 //! over TCP.
 
+use bincode::SizeLimit;
 use rustc_serialize::{Decodable, Encodable};
 use std::collections::ring_buf::RingBuf;
 use std::error::Error;
-use std::io::{Acceptor, IoError, IoErrorKind, IoResult, Listener, TcpStream};
+use std::io::{Acceptor, IoError, IoErrorKind, IoResult, Listener, Reader, TcpStream};
 use std::io::net::ip::ToSocketAddr;
 use std::io::net::tcp::TcpAcceptor;
 use std::sync::{Arc, Future, Mutex, mpsc};
 use std::thread::Thread;
-use super::{SenderError, SendRequest, ReceiverError};
+use net;
+use super::{BodyReader, ChannelOptions, ConcurrencyPolicy, Received, SenderError, SendRequest, ReceiverError};
 
 /// A client sender for sending messages over TCP.
 #[derive(Clone)]
@@ -23,7 +25,17 @@ impl<T> super::Sender<T> for ClientSender<T> where T: Encodable + Send {
     /// succeeded or failed.
     fn send(&mut self, t: T) -> Future<Result<(), SenderError<T>>> {
         let (fi, fo) = mpsc::channel();
-        match self.0.send((t, fi)) {
+        match self.0.send((t, None, fi)) {
+            Ok(_) => Future::from_receiver(fo),
+            Err(_) => panic!("can't send, receiver hung up"),
+        }
+    }
+
+    /// Send a value along with a streaming body, read from `body` and
+    /// pumped to the peer in chunks after the value's own frame.
+    fn send_with_body(&mut self, t: T, body: Box<Reader + Send>) -> Future<Result<(), SenderError<T>>> {
+        let (fi, fo) = mpsc::channel();
+        match self.0.send((t, Some(body), fi)) {
             Ok(_) => Future::from_receiver(fo),
             Err(_) => panic!("can't send, receiver hung up"),
         }
@@ -31,13 +43,26 @@ impl<T> super::Sender<T> for ClientSender<T> where T: Encodable + Send {
 }
 
 /// A client receiver for receiving server responses over TCP.
-pub struct ClientReceiver<S: Decodable + Send>(mpsc::Receiver<Result<S, ReceiverError<S>>>);
+pub struct ClientReceiver<S: Decodable + Send>(mpsc::Receiver<Result<Received<S>, ReceiverError<S>>>);
 
 impl<S> super::Receiver<S> for ClientReceiver<S> where S: Decodable + Send {
     /// Try to receive a server response.
     fn try_recv(&mut self) -> Result<S, ReceiverError<S>> {
         match self.0.recv() {
-            Ok(x) => x,
+            Ok(Ok(Received::Plain(s))) => Ok(s),
+            Ok(Ok(Received::WithBody(s, _))) => Ok(s),
+            Ok(Err(e)) => Err(e),
+            Err(_) => panic!("sender hung up!"),
+        }
+    }
+
+    /// Try to receive a server response sent with an attached streaming
+    /// body.
+    fn try_recv_with_body(&mut self) -> Result<(S, BodyReader), ReceiverError<S>> {
+        match self.0.recv() {
+            Ok(Ok(Received::Plain(s))) => Ok((s, BodyReader::empty())),
+            Ok(Ok(Received::WithBody(s, r))) => Ok((s, r)),
+            Ok(Err(e)) => Err(e),
             Err(_) => panic!("sender hung up!"),
         }
     }
@@ -49,36 +74,26 @@ impl<S> super::Receiver<S> for ClientReceiver<S> where S: Decodable + Send {
 /// address, and returns a sender/receiver pair if the connection was made.
 #[allow(unused_must_use)]
 pub fn client_channel<A: ToSocketAddr, T: Encodable + Send, S: Decodable + Send>(addr: A) -> Result<(ClientSender<T>, ClientReceiver<S>), IoError> {
-    let stream = try!(TcpStream::connect(addr));
+    client_channel_with_options(addr, ChannelOptions::default())
+}
+
+/// Same as `client_channel`, but lets the caller configure the maximum
+/// encoded size of a single frame in either direction.
+#[allow(unused_must_use)]
+pub fn client_channel_with_limit<A: ToSocketAddr, T: Encodable + Send, S: Decodable + Send>(addr: A, size_limit: SizeLimit) -> Result<(ClientSender<T>, ClientReceiver<S>), IoError> {
+    client_channel_with_options(addr, ChannelOptions { size_limit: size_limit, ..ChannelOptions::default() })
+}
+
+/// Same as `client_channel`, but lets the caller configure the size limit
+/// and the `Ping`/`Pong` heartbeat interval and timeout.
+#[allow(unused_must_use)]
+pub fn client_channel_with_options<A: ToSocketAddr, T: Encodable + Send, S: Decodable + Send>(addr: A, options: ChannelOptions) -> Result<(ClientSender<T>, ClientReceiver<S>), IoError> {
+    let mut stream = try!(TcpStream::connect(addr));
+    stream.set_timeout(Some(options.heartbeat_interval_ms));
     let (ss, sr) = mpsc::channel::<SendRequest<T>>();
-    {
-        let mut stream = stream.clone();
-        Thread::spawn(move || {
-            for (t, fi) in sr.iter() {
-                fi.send(super::write_item(&mut stream, &t));
-            }
-        });
-    }
-    let (rs, rr) = mpsc::channel::<Result<S, ReceiverError<S>>>();
-    {
-        let mut stream = stream.clone();
-        Thread::spawn(move || {
-            loop {
-                match stream.read_le_uint() {
-                    Err(ref e) if e.kind == IoErrorKind::TimedOut => (),
-                    Err(ref e) if e.kind == IoErrorKind::EndOfFile => return,
-                    Err(e) => match rs.send(Err(ReceiverError::Io(e))) {
-                        Ok(_) => (),
-                        Err(e) => panic!("{:?}", e),
-                    },
-                    Ok(size) => match rs.send(super::read_item(&mut stream, size)) {
-                        Ok(_) => (),
-                        Err(e) => panic!("{:?}", e),
-                    },
-                }
-            }
-        });
-    }
+    net::spawn_client_writer(stream.clone(), sr, options.size_limit);
+    let (rs, rr) = mpsc::channel::<Result<super::Received<S>, ReceiverError<S>>>();
+    net::spawn_client_reader(stream.clone(), rs, options);
     Ok((ClientSender(ss), ClientReceiver(rr)))
 }
 
@@ -92,8 +107,8 @@ struct ServerReceiver<S: Decodable + Send>(mpsc::Receiver<Result<S, Box<Error>>>
 
 type ClientConnection<T, S> = (ServerSender<T>, ServerReceiver<S>);
 
-impl<T, S> Acceptor<ClientConnection<T, S>> for ClientAcceptor where T: Encodable + Send, S: Decodable + Send {
-    fn accept(&mut self) -> IoResult<ClientConnection<T, S>> {
+impl<T, S> ClientAcceptor where T: Encodable + Send, S: Decodable + Send {
+    fn accept_with_limit(&mut self, size_limit: SizeLimit) -> IoResult<ClientConnection<T, S>> {
         let stream = try!(self.inner.accept());
         let (ss, sr) = mpsc::channel::<SendRequest<T>>();
 
@@ -101,7 +116,7 @@ impl<T, S> Acceptor<ClientConnection<T, S>> for ClientAcceptor where T: Encodabl
             let mut stream = stream.clone();
             Thread::spawn(move || {
                 for val in sr.iter() {
-                    super::write_item(&mut stream, &val.0);
+                    super::write_item(&mut stream, &val.0, size_limit);
                     // TODO: send result on val.1?
                 }
             });
@@ -111,13 +126,25 @@ impl<T, S> Acceptor<ClientConnection<T, S>> for ClientAcceptor where T: Encodabl
         {
             let mut stream = stream.clone();
             Thread::spawn(move || {
-                match stream.read_le_uint() {
-                    Ok(size) => match super::read_item(&mut stream, size) {
-                        Ok(val) => rs.send(Ok(val)).unwrap(),
-                        Err(_) => return,
+                match super::read_frame::<S, _>(&mut stream, size_limit) {
+                    Ok(super::Frame::Data(val)) => rs.send(Ok(val)).unwrap(),
+                    // TODO: this legacy path predates streaming bodies and
+                    // the Ping/Pong heartbeat reader loop in `net`; it
+                    // doesn't support attached bodies.
+                    Ok(super::Frame::DataWithBody(val)) => rs.send(Ok(val)).unwrap(),
+                    // Likewise, this path predates the writer-side batching
+                    // in `net::spawn_client_writer` and only ever sees one
+                    // value at a time, so a `Turn` frame is unpacked here
+                    // rather than threaded all the way through.
+                    Ok(super::Frame::Turn(vals)) => {
+                        for val in vals.into_iter() {
+                            rs.send(Ok(val)).unwrap();
+                        }
                     },
-                    Err(ref e) if e.kind == IoErrorKind::TimedOut => (),
-                    Err(ref e) if e.kind == IoErrorKind::EndOfFile => return,
+                    Ok(super::Frame::Ping) | Ok(super::Frame::Pong) |
+                    Ok(super::Frame::Body(_)) | Ok(super::Frame::BodyEnd) => (),
+                    Err(ReceiverError::Io(ref e)) if e.kind == IoErrorKind::TimedOut => (),
+                    Err(ReceiverError::Io(ref e)) if e.kind == IoErrorKind::EndOfFile => return,
                     Err(e) => rs.send(Err(Box::new(e) as Box<Error>)).unwrap(),
                 }
             });
@@ -127,6 +154,12 @@ impl<T, S> Acceptor<ClientConnection<T, S>> for ClientAcceptor where T: Encodabl
     }
 }
 
+impl<T, S> Acceptor<ClientConnection<T, S>> for ClientAcceptor where T: Encodable + Send, S: Decodable + Send {
+    fn accept(&mut self) -> IoResult<ClientConnection<T, S>> {
+        self.accept_with_limit(SizeLimit::Bounded(super::DEFAULT_SIZE_LIMIT))
+    }
+}
+
 /// Listen for incoming TCP connections.
 ///
 /// The server side uses an event-based architecture, with the supported events:
@@ -145,6 +178,34 @@ pub fn server_channel<A, T, S, H, N, D>(addr: A, on_msg: H, on_new: N, on_drop:
               H: Fn(u32, S) -> T + Copy + Send,              // handle client message
               N: Fn(u32) -> () + Copy + Send,                // new client
               D: Fn(u32) -> () + Copy + Send,                // client dropped
+{
+    server_channel_with_options(addr, on_msg, on_new, on_drop, ChannelOptions::default())
+}
+
+/// Same as `server_channel`, but lets the caller configure the maximum
+/// encoded size of a single frame in either direction.
+#[allow(unused_must_use)]
+pub fn server_channel_with_limit<A, T, S, H, N, D>(addr: A, on_msg: H, on_new: N, on_drop: D, size_limit: SizeLimit) -> Result<(), Box<Error>>
+        where A: ToSocketAddr,
+              T: Encodable + Send, // outgoing
+              S: Decodable + Send,     // incoming
+              H: Fn(u32, S) -> T + Copy + Send,              // handle client message
+              N: Fn(u32) -> () + Copy + Send,                // new client
+              D: Fn(u32) -> () + Copy + Send,                // client dropped
+{
+    server_channel_with_options(addr, on_msg, on_new, on_drop, ChannelOptions { size_limit: size_limit, ..ChannelOptions::default() })
+}
+
+/// Same as `server_channel`, but lets the caller configure the size limit
+/// and the `Ping`/`Pong` heartbeat interval and timeout.
+#[allow(unused_must_use)]
+pub fn server_channel_with_options<A, T, S, H, N, D>(addr: A, on_msg: H, on_new: N, on_drop: D, options: ChannelOptions) -> Result<(), Box<Error>>
+        where A: ToSocketAddr,
+              T: Encodable + Send, // outgoing
+              S: Decodable + Send,     // incoming
+              H: Fn(u32, S) -> T + Copy + Send,              // handle client message
+              N: Fn(u32) -> () + Copy + Send,                // new client
+              D: Fn(u32) -> () + Copy + Send,                // client dropped
 {
     use std::io::net::tcp::TcpListener;
 
@@ -154,9 +215,19 @@ pub fn server_channel<A, T, S, H, N, D>(addr: A, on_msg: H, on_new: N, on_drop:
         let mut acceptor = acceptor.clone();
         let mut client_counter = 0;
         let freed_clients = Arc::new(Mutex::new(RingBuf::new()));
+        let limiter = options.max_concurrent_clients.map(|max| Arc::new(net::Semaphore::new(max)));
         for conn in acceptor.incoming() {
             match conn {
                 Ok(mut conn) => {
+                    if let Some(ref limiter) = limiter {
+                        match options.concurrency_policy {
+                            ConcurrencyPolicy::Block => limiter.acquire(),
+                            ConcurrencyPolicy::Reject => if !limiter.try_acquire() {
+                                drop(conn);
+                                continue;
+                            },
+                        }
+                    }
                     let client_id = match freed_clients.lock().unwrap().pop_front() {
                         Some(id) => id,
                         None => {
@@ -164,32 +235,9 @@ pub fn server_channel<A, T, S, H, N, D>(addr: A, on_msg: H, on_new: N, on_drop:
                             client_counter
                         },
                     };
+                    conn.set_timeout(Some(options.heartbeat_interval_ms));
                     on_new(client_id);
-                    let freed_clients = freed_clients.clone();
-                    Thread::spawn(move || {
-                        loop {
-                            let item = match conn.read_le_uint() {
-                                Ok(size) => match super::read_item(&mut conn, size) {
-                                    Ok(item) => item,
-                                    Err(e) => panic!(e),
-                                },
-                                Err(ref e) if e.kind == IoErrorKind::TimedOut => {
-                                    continue;
-                                },
-                                Err(ref e) if e.kind == IoErrorKind::EndOfFile => {
-                                    freed_clients.lock().unwrap().push_back(client_id.clone());
-                                    on_drop(client_id);
-                                    return;
-                                },
-                                Err(e) => {
-                                    freed_clients.lock().unwrap().push_back(client_id.clone());
-                                    panic!("{}", e);
-                                },
-                            };
-                            let resp = on_msg(client_id, item);
-                            super::write_item(&mut conn, &resp);
-                        }
-                    });
+                    net::spawn_server_connection(conn, client_id, on_msg, on_drop, freed_clients.clone(), limiter.clone(), options);
                 },
                 Err(ref e) if e.kind == IoErrorKind::EndOfFile => break,
                 Err(e) => panic!(e),
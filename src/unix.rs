@@ -0,0 +1,190 @@
+#![unstable]
+//! Module `unix` provides support for channels that communicate over Unix
+//! domain sockets.
+//!
+//! It mirrors `superchan::tcp` -- its own `ClientSender`/`ClientReceiver`
+//! implementing the same `Sender`/`Receiver` traits, and the same
+//! `on_msg`/`on_new`/`on_drop` event model on the server side -- but
+//! connects processes on the same host via a `UnixStream`/`UnixListener`
+//! instead of going through the loopback TCP stack. The framing and
+//! thread-spawning logic is shared with `tcp` through the `net` module.
+
+use bincode::SizeLimit;
+use rustc_serialize::{Decodable, Encodable};
+use std::collections::ring_buf::RingBuf;
+use std::error::Error;
+use std::io::{BytesContainer, IoError, IoErrorKind, Reader};
+use std::io::net::pipe::{UnixListener, UnixStream};
+use std::sync::{Arc, Future, Mutex, mpsc};
+use net;
+use super::{BodyReader, ChannelOptions, ConcurrencyPolicy, Received, SenderError, SendRequest, ReceiverError};
+
+/// A client sender for sending messages over a Unix domain socket.
+#[derive(Clone)]
+pub struct ClientSender<T: Encodable + Send>(mpsc::Sender<SendRequest<T>>);
+
+impl<T> super::Sender<T> for ClientSender<T> where T: Encodable + Send {
+    /// Send a value along the channel.
+    ///
+    /// The returned Future will only have a value available after the send has either
+    /// succeeded or failed.
+    fn send(&mut self, t: T) -> Future<Result<(), SenderError<T>>> {
+        let (fi, fo) = mpsc::channel();
+        match self.0.send((t, None, fi)) {
+            Ok(_) => Future::from_receiver(fo),
+            Err(_) => panic!("can't send, receiver hung up"),
+        }
+    }
+
+    /// Send a value along with a streaming body, read from `body` and
+    /// pumped to the peer in chunks after the value's own frame.
+    fn send_with_body(&mut self, t: T, body: Box<Reader + Send>) -> Future<Result<(), SenderError<T>>> {
+        let (fi, fo) = mpsc::channel();
+        match self.0.send((t, Some(body), fi)) {
+            Ok(_) => Future::from_receiver(fo),
+            Err(_) => panic!("can't send, receiver hung up"),
+        }
+    }
+}
+
+/// A client receiver for receiving server responses over a Unix domain socket.
+pub struct ClientReceiver<S: Decodable + Send>(mpsc::Receiver<Result<Received<S>, ReceiverError<S>>>);
+
+impl<S> super::Receiver<S> for ClientReceiver<S> where S: Decodable + Send {
+    /// Try to receive a server response.
+    fn try_recv(&mut self) -> Result<S, ReceiverError<S>> {
+        match self.0.recv() {
+            Ok(Ok(Received::Plain(s))) => Ok(s),
+            Ok(Ok(Received::WithBody(s, _))) => Ok(s),
+            Ok(Err(e)) => Err(e),
+            Err(_) => panic!("sender hung up!"),
+        }
+    }
+
+    /// Try to receive a server response sent with an attached streaming
+    /// body.
+    fn try_recv_with_body(&mut self) -> Result<(S, BodyReader), ReceiverError<S>> {
+        match self.0.recv() {
+            Ok(Ok(Received::Plain(s))) => Ok((s, BodyReader::empty())),
+            Ok(Ok(Received::WithBody(s, r))) => Ok((s, r)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => panic!("sender hung up!"),
+        }
+    }
+}
+
+/// Create a channel over a new Unix domain socket connection.
+///
+/// This method attempts to connect to an existing server listening on the
+/// socket at `path`, and returns a sender/receiver pair if the connection
+/// was made.
+#[allow(unused_must_use)]
+pub fn client_channel<P: BytesContainer, T: Encodable + Send, S: Decodable + Send>(path: P) -> Result<(ClientSender<T>, ClientReceiver<S>), IoError> {
+    client_channel_with_options(path, ChannelOptions::default())
+}
+
+/// Same as `client_channel`, but lets the caller configure the maximum
+/// encoded size of a single frame in either direction.
+#[allow(unused_must_use)]
+pub fn client_channel_with_limit<P: BytesContainer, T: Encodable + Send, S: Decodable + Send>(path: P, size_limit: SizeLimit) -> Result<(ClientSender<T>, ClientReceiver<S>), IoError> {
+    client_channel_with_options(path, ChannelOptions { size_limit: size_limit, ..ChannelOptions::default() })
+}
+
+/// Same as `client_channel`, but lets the caller configure the size limit
+/// and the `Ping`/`Pong` heartbeat interval and timeout.
+#[allow(unused_must_use)]
+pub fn client_channel_with_options<P: BytesContainer, T: Encodable + Send, S: Decodable + Send>(path: P, options: ChannelOptions) -> Result<(ClientSender<T>, ClientReceiver<S>), IoError> {
+    let mut stream = try!(UnixStream::connect(&path));
+    stream.set_timeout(Some(options.heartbeat_interval_ms));
+    let (ss, sr) = mpsc::channel::<SendRequest<T>>();
+    net::spawn_client_writer(stream.clone(), sr, options.size_limit);
+    let (rs, rr) = mpsc::channel::<Result<Received<S>, ReceiverError<S>>>();
+    net::spawn_client_reader(stream.clone(), rs, options);
+    Ok((ClientSender(ss), ClientReceiver(rr)))
+}
+
+/// Listen for incoming Unix domain socket connections.
+///
+/// The server side uses the same event-based architecture as `tcp::server_channel`,
+/// with the supported events:
+///
+///  * `on_msg`: notification of a client message
+///  * `on_new`: notification of a new client connection
+///  * `on_drop`: notification of a client hanging up
+///
+/// Events that you don't care about can be ignored by passing in `|_|{}`, which is an
+/// empty closure.
+#[allow(unused_must_use)]
+pub fn server_channel<P, T, S, H, N, D>(path: P, on_msg: H, on_new: N, on_drop: D) -> Result<(), Box<Error>>
+        where P: BytesContainer,
+              T: Encodable + Send, // outgoing
+              S: Decodable + Send,     // incoming
+              H: Fn(u32, S) -> T + Copy + Send,              // handle client message
+              N: Fn(u32) -> () + Copy + Send,                // new client
+              D: Fn(u32) -> () + Copy + Send,                // client dropped
+{
+    server_channel_with_options(path, on_msg, on_new, on_drop, ChannelOptions::default())
+}
+
+/// Same as `server_channel`, but lets the caller configure the maximum
+/// encoded size of a single frame in either direction.
+#[allow(unused_must_use)]
+pub fn server_channel_with_limit<P, T, S, H, N, D>(path: P, on_msg: H, on_new: N, on_drop: D, size_limit: SizeLimit) -> Result<(), Box<Error>>
+        where P: BytesContainer,
+              T: Encodable + Send, // outgoing
+              S: Decodable + Send,     // incoming
+              H: Fn(u32, S) -> T + Copy + Send,              // handle client message
+              N: Fn(u32) -> () + Copy + Send,                // new client
+              D: Fn(u32) -> () + Copy + Send,                // client dropped
+{
+    server_channel_with_options(path, on_msg, on_new, on_drop, ChannelOptions { size_limit: size_limit, ..ChannelOptions::default() })
+}
+
+/// Same as `server_channel`, but lets the caller configure the size limit
+/// and the `Ping`/`Pong` heartbeat interval and timeout.
+#[allow(unused_must_use)]
+pub fn server_channel_with_options<P, T, S, H, N, D>(path: P, on_msg: H, on_new: N, on_drop: D, options: ChannelOptions) -> Result<(), Box<Error>>
+        where P: BytesContainer,
+              T: Encodable + Send, // outgoing
+              S: Decodable + Send,     // incoming
+              H: Fn(u32, S) -> T + Copy + Send,              // handle client message
+              N: Fn(u32) -> () + Copy + Send,                // new client
+              D: Fn(u32) -> () + Copy + Send,                // client dropped
+{
+    let listener = try!(UnixListener::bind(&path));
+    let acceptor = try!(listener.listen());
+    {
+        let mut acceptor = acceptor.clone();
+        let mut client_counter = 0;
+        let freed_clients = Arc::new(Mutex::new(RingBuf::new()));
+        let limiter = options.max_concurrent_clients.map(|max| Arc::new(net::Semaphore::new(max)));
+        for conn in acceptor.incoming() {
+            match conn {
+                Ok(mut conn) => {
+                    if let Some(ref limiter) = limiter {
+                        match options.concurrency_policy {
+                            ConcurrencyPolicy::Block => limiter.acquire(),
+                            ConcurrencyPolicy::Reject => if !limiter.try_acquire() {
+                                drop(conn);
+                                continue;
+                            },
+                        }
+                    }
+                    let client_id = match freed_clients.lock().unwrap().pop_front() {
+                        Some(id) => id,
+                        None => {
+                            client_counter = client_counter + 1;
+                            client_counter
+                        },
+                    };
+                    conn.set_timeout(Some(options.heartbeat_interval_ms));
+                    on_new(client_id);
+                    net::spawn_server_connection(conn, client_id, on_msg, on_drop, freed_clients.clone(), limiter.clone(), options);
+                },
+                Err(ref e) if e.kind == IoErrorKind::EndOfFile => break,
+                Err(e) => panic!(e),
+            }
+        }
+    }
+    Ok(())
+}